@@ -0,0 +1,162 @@
+// Copyright 2015-2021 Swim Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Error types produced while parsing, negotiating, or driving a WebSocket handshake.
+
+use http::header::HeaderName;
+use http::StatusCode;
+use std::fmt;
+
+/// The broad category of failure behind an [`Error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The handshake's HTTP framing or headers were invalid.
+    Http,
+    /// A negotiated extension failed to negotiate or run.
+    Extension,
+    /// The underlying stream failed.
+    Io,
+}
+
+/// An error produced while parsing, negotiating, or driving a WebSocket handshake.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    cause: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl Error {
+    /// Wraps `cause` as an `Error` of the given `kind`.
+    pub fn with_cause<E>(kind: ErrorKind, cause: E) -> Error
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Error {
+            kind,
+            cause: cause.into(),
+        }
+    }
+
+    /// The broad category this error falls under.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.cause)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.cause.as_ref())
+    }
+}
+
+impl From<HttpError> for Error {
+    fn from(e: HttpError) -> Error {
+        Error::with_cause(ErrorKind::Http, e)
+    }
+}
+
+impl From<httparse::Error> for Error {
+    fn from(e: httparse::Error) -> Error {
+        Error::with_cause(ErrorKind::Http, e)
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(e: http::Error) -> Error {
+        Error::with_cause(ErrorKind::Http, e)
+    }
+}
+
+impl From<http::uri::InvalidUri> for Error {
+    fn from(e: http::uri::InvalidUri) -> Error {
+        Error::with_cause(ErrorKind::Http, e)
+    }
+}
+
+// Required by `tokio_util::codec::Decoder`'s `type Error: From<io::Error>` bound, which both
+// `RequestParser` and `ProtocolSniffer` rely on.
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::with_cause(ErrorKind::Io, e)
+    }
+}
+
+impl From<InvalidHeader> for Error {
+    fn from(e: InvalidHeader) -> Error {
+        Error::with_cause(ErrorKind::Http, HttpError::from(e))
+    }
+}
+
+/// A header that failed validation while mapping an `httparse` request into an [`http`] one.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("invalid header: {0}")]
+pub struct InvalidHeader(pub String);
+
+impl From<InvalidHeader> for HttpError {
+    fn from(e: InvalidHeader) -> HttpError {
+        HttpError::InvalidHeaderValue(e.0)
+    }
+}
+
+/// An HTTP-level error encountered while parsing or negotiating a handshake request.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum HttpError {
+    /// A header was present but did not have the expected value.
+    #[error("invalid header: {0}")]
+    InvalidHeader(HeaderName),
+    /// A header failed to parse into a valid name/value pair.
+    #[error("invalid header: {0}")]
+    InvalidHeaderValue(String),
+    /// A required header was missing.
+    #[error("missing header: {0}")]
+    MissingHeader(HeaderName),
+    /// The request was not HTTP/1.1.
+    #[error("unsupported HTTP version: {0:?}")]
+    HttpVersion(Option<u8>),
+    /// The request method was not one this handshake path accepts.
+    #[error("unsupported HTTP method: {0:?}")]
+    HttpMethod(Option<String>),
+    /// The request's URI could not be parsed.
+    #[error("malformed request URI: {0:?}")]
+    MalformattedUri(Option<String>),
+    /// An [`Interceptor`](crate::handshake::Interceptor) rejected the handshake; the rejection
+    /// response has already been written back to the client.
+    #[error("handshake rejected")]
+    HandshakeRejected,
+    /// Following a redirect would have downgraded from `wss` to `ws` without explicit opt-in.
+    #[error("redirected to an insecure location: {0}")]
+    InsecureRedirect(String),
+    /// A redirect chain revisited a `Uri` it had already followed.
+    #[error("redirect loop detected at: {0}")]
+    RedirectLoop(String),
+    /// A redirect chain exceeded the configured hop limit.
+    #[error("exceeded the maximum of {0} redirects")]
+    TooManyRedirects(u32),
+    /// The server responded with a status other than `101 Switching Protocols` (or, for a `3xx`,
+    /// no [`RedirectOptions`](crate::handshake::RedirectOptions) were supplied to follow it).
+    #[error("unexpected response status: {0}")]
+    UnexpectedStatus(StatusCode),
+}
+
+impl From<http::uri::InvalidUri> for HttpError {
+    fn from(e: http::uri::InvalidUri) -> HttpError {
+        HttpError::MalformattedUri(Some(e.to_string()))
+    }
+}
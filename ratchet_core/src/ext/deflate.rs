@@ -0,0 +1,498 @@
+// Copyright 2015-2021 Swim Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An implementation of the `permessage-deflate` extension, as described in
+//! [RFC 7692](https://datatracker.ietf.org/doc/html/rfc7692).
+
+use bytes::BytesMut;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use ratchet_ext::{
+    Extension, ExtensionDecoder, ExtensionEncoder, ExtensionProvider, FrameHeader, Header,
+    HeaderMap, HeaderValue, RsvBits,
+};
+use std::fmt;
+use std::fmt::Write;
+
+const EXT_NAME: &str = "permessage-deflate";
+const SERVER_NO_CONTEXT_TAKEOVER: &str = "server_no_context_takeover";
+const CLIENT_NO_CONTEXT_TAKEOVER: &str = "client_no_context_takeover";
+const SERVER_MAX_WINDOW_BITS: &str = "server_max_window_bits";
+const CLIENT_MAX_WINDOW_BITS: &str = "client_max_window_bits";
+// flate2's `new_with_window_bits` panics outside of `9..=15`; `8` is technically a valid
+// `permessage-deflate` offer per RFC 7692, but not a window size flate2 can act on, so it is
+// rejected like any other out-of-range offer.
+const MIN_WINDOW_BITS: u8 = 9;
+const MAX_WINDOW_BITS: u8 = 15;
+const EMPTY_DEFLATE_BLOCK: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Configuration for the `permessage-deflate` extension.
+///
+/// The defaults negotiate no context takeover on either side and the maximum
+/// window size, which is the most interoperable configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateConfig {
+    /// Whether the server's LZ77 window is reset between messages.
+    pub server_no_context_takeover: bool,
+    /// Whether the client's LZ77 window is reset between messages.
+    pub client_no_context_takeover: bool,
+    /// The size, in bits, of the server's LZ77 window. Must be between 9 and 15 inclusive.
+    pub server_max_window_bits: u8,
+    /// The size, in bits, of the client's LZ77 window. Must be between 9 and 15 inclusive.
+    pub client_max_window_bits: u8,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        DeflateConfig {
+            server_no_context_takeover: true,
+            client_no_context_takeover: true,
+            server_max_window_bits: MAX_WINDOW_BITS,
+            client_max_window_bits: MAX_WINDOW_BITS,
+        }
+    }
+}
+
+/// An error produced while negotiating or running the `permessage-deflate` extension.
+#[derive(Debug, thiserror::Error)]
+pub enum DeflateExtensionError {
+    /// The offer requested a window size that this implementation does not support.
+    #[error("invalid window bits requested: {0}")]
+    InvalidMaxWindowBits(u8),
+    /// The deflate stream produced a fatal error while compressing or decompressing.
+    #[error("a deflate error occurred: {0}")]
+    Deflate(String),
+}
+
+/// A provider for the `permessage-deflate` extension described in RFC 7692.
+///
+/// This negotiates the `Sec-WebSocket-Extensions` offer(s) exchanged during the handshake,
+/// selecting the `permessage-deflate` token if present and validating any requested parameters
+/// against `config`. Window bits outside of the 9-15 range are rejected.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PerMessageDeflate {
+    config: DeflateConfig,
+}
+
+impl PerMessageDeflate {
+    /// Constructs a new `PerMessageDeflate` provider with the given configuration.
+    pub fn new(config: DeflateConfig) -> PerMessageDeflate {
+        PerMessageDeflate { config }
+    }
+}
+
+impl ExtensionProvider for PerMessageDeflate {
+    type Extension = DeflateExtension;
+    type Error = DeflateExtensionError;
+
+    fn apply_headers(&self, headers: &mut HeaderMap) {
+        if let Ok(value) = HeaderValue::from_str(&render_offer(&self.config)) {
+            headers.insert(http::header::SEC_WEBSOCKET_EXTENSIONS, value);
+        }
+    }
+
+    fn negotiate_client(
+        &self,
+        headers: &[Header],
+    ) -> Result<Option<Self::Extension>, Self::Error> {
+        for offer in headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case("Sec-WebSocket-Extensions"))
+        {
+            let value = String::from_utf8_lossy(offer.value);
+            for extension in value.split(',') {
+                let params = match parse_offer(extension) {
+                    Some(params) => params,
+                    None => continue,
+                };
+                let negotiated = negotiate_params(&self.config, params)?;
+                return Ok(Some(DeflateExtension::new(negotiated)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn negotiate_server(
+        &self,
+        headers: &[Header],
+    ) -> Result<Option<(Self::Extension, HeaderValue)>, Self::Error> {
+        let offers = headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case("Sec-WebSocket-Extensions"));
+
+        for offer in offers {
+            let value = String::from_utf8_lossy(offer.value);
+            for extension in value.split(',') {
+                let params = match parse_offer(extension) {
+                    Some(params) => params,
+                    None => continue,
+                };
+                // A malformed parameter on this offer is declined, not fatal: RFC 7692 section
+                // 5 lets the server ignore an unacceptable offer and consider the next one rather
+                // than failing the whole handshake.
+                let negotiated = match negotiate_params(&self.config, params) {
+                    Ok(negotiated) => negotiated,
+                    Err(_) => continue,
+                };
+                let header_value =
+                    HeaderValue::from_str(&render_response(&negotiated)).map_err(|_| {
+                        DeflateExtensionError::Deflate("failed to encode response header".into())
+                    })?;
+                return Ok(Some((DeflateExtension::new(negotiated), header_value)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NegotiatedParams {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    server_max_window_bits: u8,
+    client_max_window_bits: u8,
+}
+
+/// Splits a single offer of the `Sec-WebSocket-Extensions` header into its parameters, returning
+/// `None` if the offer is not `permessage-deflate`.
+fn parse_offer(extension: &str) -> Option<Vec<(String, Option<String>)>> {
+    let mut parts = extension.split(';').map(str::trim);
+    let name = parts.next()?;
+    if !name.eq_ignore_ascii_case(EXT_NAME) {
+        return None;
+    }
+
+    Some(
+        parts
+            .map(|part| match part.split_once('=') {
+                Some((key, value)) => (
+                    key.trim().to_ascii_lowercase(),
+                    Some(value.trim().trim_matches('"').to_string()),
+                ),
+                None => (part.trim().to_ascii_lowercase(), None),
+            })
+            .collect(),
+    )
+}
+
+fn negotiate_params(
+    config: &DeflateConfig,
+    params: Vec<(String, Option<String>)>,
+) -> Result<NegotiatedParams, DeflateExtensionError> {
+    let mut negotiated = NegotiatedParams {
+        server_no_context_takeover: config.server_no_context_takeover,
+        client_no_context_takeover: config.client_no_context_takeover,
+        server_max_window_bits: config.server_max_window_bits,
+        client_max_window_bits: config.client_max_window_bits,
+    };
+
+    for (key, value) in params {
+        match key.as_str() {
+            SERVER_NO_CONTEXT_TAKEOVER => negotiated.server_no_context_takeover = true,
+            CLIENT_NO_CONTEXT_TAKEOVER => negotiated.client_no_context_takeover = true,
+            SERVER_MAX_WINDOW_BITS => {
+                negotiated.server_max_window_bits =
+                    negotiate_window_bits(value, config.server_max_window_bits)?;
+            }
+            CLIENT_MAX_WINDOW_BITS => {
+                negotiated.client_max_window_bits =
+                    negotiate_window_bits(value, config.client_max_window_bits)?;
+            }
+            _ => {
+                // Unknown parameters are ignored so that future extensions to the spec do not
+                // cause outright negotiation failure.
+            }
+        }
+    }
+
+    Ok(negotiated)
+}
+
+fn negotiate_window_bits(value: Option<String>, default: u8) -> Result<u8, DeflateExtensionError> {
+    match value {
+        Some(value) => {
+            let bits = value
+                .parse::<u8>()
+                .map_err(|_| DeflateExtensionError::InvalidMaxWindowBits(0))?;
+            if (MIN_WINDOW_BITS..=MAX_WINDOW_BITS).contains(&bits) {
+                Ok(bits.min(default))
+            } else {
+                Err(DeflateExtensionError::InvalidMaxWindowBits(bits))
+            }
+        }
+        None => Ok(default),
+    }
+}
+
+fn render_response(params: &NegotiatedParams) -> String {
+    let mut out = String::from(EXT_NAME);
+    if params.server_no_context_takeover {
+        let _ = write!(out, "; {}", SERVER_NO_CONTEXT_TAKEOVER);
+    }
+    if params.client_no_context_takeover {
+        let _ = write!(out, "; {}", CLIENT_NO_CONTEXT_TAKEOVER);
+    }
+    let _ = write!(
+        out,
+        "; {}={}",
+        SERVER_MAX_WINDOW_BITS, params.server_max_window_bits
+    );
+    let _ = write!(
+        out,
+        "; {}={}",
+        CLIENT_MAX_WINDOW_BITS, params.client_max_window_bits
+    );
+    out
+}
+
+/// Renders `config` as a `Sec-WebSocket-Extensions` offer, advertised by a client before any
+/// parameters have been negotiated with a peer.
+fn render_offer(config: &DeflateConfig) -> String {
+    render_response(&NegotiatedParams {
+        server_no_context_takeover: config.server_no_context_takeover,
+        client_no_context_takeover: config.client_no_context_takeover,
+        server_max_window_bits: config.server_max_window_bits,
+        client_max_window_bits: config.client_max_window_bits,
+    })
+}
+
+/// A negotiated instance of the `permessage-deflate` extension, holding the compression state
+/// for a single WebSocket connection.
+pub struct DeflateExtension {
+    params: NegotiatedParams,
+    compress: Compress,
+    decompress: Decompress,
+}
+
+impl fmt::Debug for DeflateExtension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeflateExtension")
+            .field("params", &self.params)
+            .finish()
+    }
+}
+
+impl DeflateExtension {
+    fn new(params: NegotiatedParams) -> DeflateExtension {
+        DeflateExtension {
+            compress: new_compressor(&params),
+            decompress: new_decompressor(&params),
+            params,
+        }
+    }
+
+    /// Resets the compressor's LZ77 window, as required when context takeover is disabled.
+    fn reset_compressor(&mut self) {
+        if self.params.server_no_context_takeover {
+            self.compress = new_compressor(&self.params);
+        }
+    }
+
+    /// Resets the decompressor's LZ77 window, as required when context takeover is disabled.
+    fn reset_decompressor(&mut self) {
+        if self.params.client_no_context_takeover {
+            self.decompress = new_decompressor(&self.params);
+        }
+    }
+}
+
+/// Builds a compressor bounded to the negotiated `server_max_window_bits`.
+fn new_compressor(params: &NegotiatedParams) -> Compress {
+    Compress::new_with_window_bits(Compression::default(), false, params.server_max_window_bits)
+}
+
+/// Builds a decompressor bounded to the negotiated `client_max_window_bits`.
+fn new_decompressor(params: &NegotiatedParams) -> Decompress {
+    Decompress::new_with_window_bits(false, params.client_max_window_bits)
+}
+
+impl Extension for DeflateExtension {
+    fn bits(&self) -> RsvBits {
+        RsvBits {
+            rsv1: true,
+            rsv2: false,
+            rsv3: false,
+        }
+    }
+}
+
+impl ExtensionEncoder for DeflateExtension {
+    type Error = DeflateExtensionError;
+
+    /// Compresses `payload` in place with a raw DEFLATE stream, stripping the trailing empty
+    /// block, and sets RSV1 on the frame's header.
+    fn encode(
+        &mut self,
+        payload: &mut BytesMut,
+        header: &mut FrameHeader,
+    ) -> Result<(), Self::Error> {
+        let input = payload.split().freeze();
+        let mut output = Vec::with_capacity(input.len());
+
+        compress_all(&mut self.compress, &input, &mut output)?;
+        debug_assert!(output.ends_with(&EMPTY_DEFLATE_BLOCK));
+        output.truncate(output.len() - EMPTY_DEFLATE_BLOCK.len());
+
+        payload.extend_from_slice(&output);
+        self.reset_compressor();
+        header.rsv1 = true;
+
+        Ok(())
+    }
+}
+
+impl ExtensionDecoder for DeflateExtension {
+    type Error = DeflateExtensionError;
+
+    /// Re-appends the empty DEFLATE block stripped by the peer's encoder and inflates `payload`
+    /// in place. Must only be called for frames with RSV1 set.
+    fn decode(
+        &mut self,
+        payload: &mut BytesMut,
+        _header: &mut FrameHeader,
+    ) -> Result<(), Self::Error> {
+        payload.extend_from_slice(&EMPTY_DEFLATE_BLOCK);
+        let input = payload.split().freeze();
+        let mut output = Vec::new();
+
+        decompress_all(&mut self.decompress, &input, &mut output)?;
+
+        payload.extend_from_slice(&output);
+        self.reset_decompressor();
+
+        Ok(())
+    }
+}
+
+fn compress_all(
+    compress: &mut Compress,
+    mut input: &[u8],
+    output: &mut Vec<u8>,
+) -> Result<(), DeflateExtensionError> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        let before_in = compress.total_in();
+        let before_out = compress.total_out();
+
+        let status = compress
+            .compress(input, &mut chunk, FlushCompress::Sync)
+            .map_err(|e| DeflateExtensionError::Deflate(e.to_string()))?;
+
+        let consumed = (compress.total_in() - before_in) as usize;
+        let produced = (compress.total_out() - before_out) as usize;
+        input = &input[consumed..];
+        output.extend_from_slice(&chunk[..produced]);
+
+        if status == Status::StreamEnd || (input.is_empty() && produced < chunk.len()) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn decompress_all(
+    decompress: &mut Decompress,
+    mut input: &[u8],
+    output: &mut Vec<u8>,
+) -> Result<(), DeflateExtensionError> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        let before_in = decompress.total_in();
+        let before_out = decompress.total_out();
+
+        let status = decompress
+            .decompress(input, &mut chunk, FlushDecompress::Sync)
+            .map_err(|e| DeflateExtensionError::Deflate(e.to_string()))?;
+
+        let consumed = (decompress.total_in() - before_in) as usize;
+        let produced = (decompress.total_out() - before_out) as usize;
+        input = &input[consumed..];
+        output.extend_from_slice(&chunk[..produced]);
+
+        if status == Status::StreamEnd || (input.is_empty() && produced < chunk.len()) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(body: &str) -> Vec<Header<'static>> {
+        vec![Header {
+            name: "Sec-WebSocket-Extensions",
+            value: Box::leak(body.as_bytes().to_vec().into_boxed_slice()),
+        }]
+    }
+
+    #[test]
+    fn negotiates_within_range_window_bits() {
+        let provider = PerMessageDeflate::default();
+        let headers =
+            offer("permessage-deflate; server_max_window_bits=10; client_max_window_bits=9");
+        let (extension, _) = provider.negotiate_server(&headers).unwrap().unwrap();
+
+        assert_eq!(extension.params.server_max_window_bits, 10);
+        assert_eq!(extension.params.client_max_window_bits, 9);
+    }
+
+    #[test]
+    fn out_of_range_window_bits_declines_offer_without_failing_handshake() {
+        let provider = PerMessageDeflate::default();
+        let headers = offer("permessage-deflate; server_max_window_bits=30");
+
+        assert!(provider.negotiate_server(&headers).unwrap().is_none());
+    }
+
+    #[test]
+    fn out_of_range_window_bits_on_one_offer_falls_through_to_the_next() {
+        let provider = PerMessageDeflate::default();
+        let headers = offer(
+            "permessage-deflate; server_max_window_bits=30, permessage-deflate; client_max_window_bits=9",
+        );
+        let (extension, _) = provider.negotiate_server(&headers).unwrap().unwrap();
+
+        assert_eq!(extension.params.client_max_window_bits, 9);
+    }
+
+    #[test]
+    fn encode_sets_rsv1_and_decode_round_trips() {
+        let mut extension = DeflateExtension::new(NegotiatedParams {
+            server_no_context_takeover: true,
+            client_no_context_takeover: true,
+            server_max_window_bits: MAX_WINDOW_BITS,
+            client_max_window_bits: MAX_WINDOW_BITS,
+        });
+
+        let original = BytesMut::from(&b"the quick brown fox jumps over the lazy dog"[..]);
+        let mut payload = original.clone();
+        let mut header = FrameHeader {
+            fin: true,
+            rsv1: false,
+            rsv2: false,
+            rsv3: false,
+            opcode: ratchet_ext::OpCode::Binary,
+        };
+
+        extension.encode(&mut payload, &mut header).unwrap();
+        assert!(header.rsv1, "permessage-deflate must set RSV1 on the first frame");
+        assert_ne!(payload, original);
+
+        extension.decode(&mut payload, &mut header).unwrap();
+        assert_eq!(payload, original);
+    }
+}
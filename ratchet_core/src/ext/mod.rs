@@ -0,0 +1,35 @@
+// Copyright 2015-2021 Swim Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! WebSocket extension negotiation.
+
+pub mod deflate;
+
+/// The extension negotiated for a connection, if any.
+pub struct NegotiatedExtension<E> {
+    extension: Option<E>,
+}
+
+impl<E> NegotiatedExtension<E> {
+    /// The negotiated extension, if one was selected during the handshake.
+    pub fn get(&self) -> Option<&E> {
+        self.extension.as_ref()
+    }
+}
+
+impl<E> From<Option<E>> for NegotiatedExtension<E> {
+    fn from(extension: Option<E>) -> Self {
+        NegotiatedExtension { extension }
+    }
+}
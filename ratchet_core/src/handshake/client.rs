@@ -0,0 +1,296 @@
+// Copyright 2015-2021 Swim Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The client side of the handshake: sending the upgrade request and validating the server's
+//! response.
+//!
+//! `subscribe` treats any non-`101` response as terminal. `subscribe_with` additionally accepts
+//! [`RedirectOptions`](crate::handshake::RedirectOptions): a `3xx` response is then resolved
+//! against the request's `Uri` and retried against the new location (see
+//! [`RedirectState`](crate::handshake::RedirectState)) instead of being treated as an error.
+
+use crate::errors::{Error, ErrorKind, HttpError};
+use crate::handshake::io::BufferedIo;
+use crate::handshake::redirect::{RedirectOptions, RedirectOutcome, RedirectState};
+use crate::handshake::{
+    get_header, validate_header_value, TryIntoRequest, TryMap, ACCEPT_KEY, BAD_STATUS_CODE,
+    UPGRADE_STR, WEBSOCKET_STR, WEBSOCKET_VERSION_STR,
+};
+use crate::ProtocolRegistry;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use http::{HeaderValue, Request, StatusCode};
+use httparse::Status;
+use rand::RngCore;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const MAX_HEADERS: usize = 32;
+const KEY_LEN: usize = 16;
+
+/// The outcome of successfully sending and validating a client-side handshake request.
+pub struct HandshakeResult {
+    /// The subprotocol the server accepted, if any.
+    pub subprotocol: Option<Bytes>,
+}
+
+/// A stream that has completed a client-side WebSocket handshake.
+pub struct UpgradedClient {
+    /// The subprotocol the server accepted, if any.
+    pub subprotocol: Option<Bytes>,
+}
+
+/// Sends a WebSocket handshake request built from `request` over `stream`, offering
+/// `subprotocols`, and validates the server's `101 Switching Protocols` response. Any other
+/// status is treated as terminal.
+pub async fn subscribe<S, R>(
+    stream: &mut S,
+    request: R,
+    subprotocols: ProtocolRegistry,
+) -> Result<UpgradedClient, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: TryIntoRequest,
+{
+    let request = request.try_into_request()?;
+    match attempt(stream, request, &subprotocols).await? {
+        RedirectOutcome::Complete(upgraded) => Ok(upgraded),
+        RedirectOutcome::Redirect { status, .. } => Err(Error::with_cause(
+            ErrorKind::Http,
+            HttpError::UnexpectedStatus(status),
+        )),
+    }
+}
+
+/// Like [`subscribe`], but follows `3xx` redirects per `redirect_options` instead of treating
+/// them as terminal. Every attempt, including one for a redirect to another host, is written to
+/// the same `stream`; no new connection is opened.
+pub async fn subscribe_with<S, R>(
+    stream: &mut S,
+    request: R,
+    subprotocols: ProtocolRegistry,
+    redirect_options: RedirectOptions,
+) -> Result<UpgradedClient, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: TryIntoRequest,
+{
+    let mut request = request.try_into_request()?;
+    let mut redirects = RedirectState::new(request.uri());
+
+    loop {
+        let uri = request.uri().clone();
+        let headers = request.headers().clone();
+
+        match attempt(&mut *stream, request, &subprotocols).await? {
+            RedirectOutcome::Complete(upgraded) => return Ok(upgraded),
+            RedirectOutcome::Redirect { location, .. } => {
+                request = redirects.resolve(&redirect_options, &uri, &headers, location)?;
+            }
+        }
+    }
+}
+
+/// Performs a single handshake attempt: writes the upgrade request and classifies the response
+/// as either a completed upgrade or a redirect for the caller to resolve.
+async fn attempt<S>(
+    stream: &mut S,
+    mut request: Request<()>,
+    subprotocols: &ProtocolRegistry,
+) -> Result<RedirectOutcome<UpgradedClient>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let key = generate_key();
+    apply_request_headers(&mut request, &key, subprotocols);
+
+    let mut buf = BytesMut::new();
+    write_request(&mut *stream, &mut buf, &request).await?;
+
+    buf.clear();
+    let mut io = BufferedIo::new(&mut *stream, &mut buf);
+    loop {
+        io.read().await?;
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+
+        match response.parse(&io.buffer[..]) {
+            Ok(Status::Complete(count)) => {
+                let outcome = classify_response(&response, &key, subprotocols)?;
+                io.advance(count);
+                return Ok(outcome);
+            }
+            Ok(Status::Partial) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Adds the headers required to offer a WebSocket upgrade to `request`.
+fn apply_request_headers(request: &mut Request<()>, key: &[u8], subprotocols: &ProtocolRegistry) {
+    let headers = request.headers_mut();
+    headers.insert(http::header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(
+        http::header::CONNECTION,
+        HeaderValue::from_static("Upgrade"),
+    );
+    headers.insert(
+        http::header::SEC_WEBSOCKET_VERSION,
+        HeaderValue::from_static(WEBSOCKET_VERSION_STR),
+    );
+    headers.insert(
+        http::header::SEC_WEBSOCKET_KEY,
+        HeaderValue::from_maybe_shared(Bytes::from(
+            base64::engine::general_purpose::STANDARD.encode(key),
+        ))
+        .expect("base64 output is always a valid header value"),
+    );
+
+    if let Some(offer) = subprotocols.offer() {
+        if let Ok(value) = HeaderValue::from_maybe_shared(offer) {
+            headers.insert(http::header::SEC_WEBSOCKET_PROTOCOL, value);
+        }
+    }
+}
+
+/// Writes `request` to `stream` as an HTTP/1.1 request line and header block.
+async fn write_request<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    request: &Request<()>,
+) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    use bytes::BufMut;
+
+    buf.clear();
+
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    buf.put_slice(b"GET ");
+    buf.put_slice(path.as_bytes());
+    buf.put_slice(b" HTTP/1.1\r\n");
+
+    if let Some(authority) = request.uri().authority() {
+        buf.put_slice(b"Host: ");
+        buf.put_slice(authority.as_str().as_bytes());
+        buf.put_slice(b"\r\n");
+    }
+
+    for (name, value) in request.headers() {
+        buf.put_slice(name.as_str().as_bytes());
+        buf.put_slice(b": ");
+        buf.put_slice(value.as_bytes());
+        buf.put_slice(b"\r\n");
+    }
+
+    buf.put_slice(b"\r\n");
+
+    let mut io = BufferedIo::new(stream, buf);
+    io.write().await
+}
+
+/// Validates the server's response and, if it is a `3xx`, hands the status/`Location` back to
+/// the caller instead of treating it as terminal.
+fn classify_response(
+    response: &httparse::Response,
+    key: &[u8],
+    subprotocols: &ProtocolRegistry,
+) -> Result<RedirectOutcome<UpgradedClient>, Error> {
+    let code = response
+        .code
+        .ok_or_else(|| Error::with_cause(ErrorKind::Http, BAD_STATUS_CODE))?;
+    let status = StatusCode::from_u16(code)
+        .map_err(|_| Error::with_cause(ErrorKind::Http, BAD_STATUS_CODE))?;
+
+    if status.is_redirection() {
+        let headers: http::HeaderMap = response.headers.try_map()?;
+        if let Some(location) = headers.get(http::header::LOCATION) {
+            return Ok(RedirectOutcome::Redirect {
+                status,
+                location: location.clone(),
+            });
+        }
+    }
+
+    if status != StatusCode::SWITCHING_PROTOCOLS {
+        return Err(Error::with_cause(
+            ErrorKind::Http,
+            HttpError::UnexpectedStatus(status),
+        ));
+    }
+
+    let headers = &response.headers;
+    validate_header_value(headers, http::header::UPGRADE, WEBSOCKET_STR)?;
+    validate_header_value(headers, http::header::CONNECTION, UPGRADE_STR)?;
+
+    let accept = get_header(headers, http::header::SEC_WEBSOCKET_ACCEPT)?;
+    if accept.as_ref() != expected_accept_key(key).as_bytes() {
+        return Err(Error::with_cause(
+            ErrorKind::Http,
+            HttpError::InvalidHeader(http::header::SEC_WEBSOCKET_ACCEPT),
+        ));
+    }
+
+    let subprotocol = negotiate_response(headers, subprotocols)?;
+
+    Ok(RedirectOutcome::Complete(UpgradedClient { subprotocol }))
+}
+
+/// Selects the subprotocol the server echoed back, validating it was one that was offered.
+fn negotiate_response(
+    headers: &[httparse::Header],
+    subprotocols: &ProtocolRegistry,
+) -> Result<Option<Bytes>, Error> {
+    match headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("Sec-WebSocket-Protocol"))
+    {
+        Some(header) => {
+            let accepted = String::from_utf8_lossy(header.value);
+            if subprotocols.contains(accepted.as_ref()) {
+                Ok(Some(Bytes::from(header.value.to_vec())))
+            } else {
+                Err(Error::with_cause(
+                    ErrorKind::Http,
+                    HttpError::InvalidHeader(http::header::SEC_WEBSOCKET_PROTOCOL),
+                ))
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// Generates the 16 random bytes of a `Sec-WebSocket-Key`, per RFC 6455 section 4.1. They are
+/// base64-encoded where the header is written.
+fn generate_key() -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+/// Computes the `Sec-WebSocket-Accept` value expected in response to `key`.
+fn expected_accept_key(key: &[u8]) -> String {
+    let encoded_key = base64::engine::general_purpose::STANDARD.encode(key);
+    let mut hasher = Sha1::new();
+    hasher.update(encoded_key.as_bytes());
+    hasher.update(ACCEPT_KEY);
+    base64::engine::general_purpose::STANDARD.encode(hasher.finalize())
+}
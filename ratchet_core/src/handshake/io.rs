@@ -0,0 +1,88 @@
+// Copyright 2015-2021 Swim Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pairs a raw stream with a shared read/write buffer for the incremental handshake decoders.
+
+use crate::errors::{Error, ErrorKind};
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The maximum number of bytes read from the underlying stream per [`BufferedIo::read`] call.
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Pairs a stream (or a `&mut` reference to one) with a caller-owned buffer, used to drive the
+/// incremental decoders in this module ([`crate::handshake::StreamingParser`]) and to write
+/// handshake responses.
+pub struct BufferedIo<'buf, I> {
+    io: I,
+    pub buffer: &'buf mut BytesMut,
+}
+
+impl<'buf, I> BufferedIo<'buf, I> {
+    /// Pairs `io` with `buffer`.
+    pub fn new(io: I, buffer: &'buf mut BytesMut) -> BufferedIo<'buf, I> {
+        BufferedIo { io, buffer }
+    }
+
+    /// Discards the first `count` bytes of the buffer, keeping anything read past them.
+    pub fn advance(&mut self, count: usize) {
+        self.buffer.advance(count);
+    }
+}
+
+impl<'buf, I> BufferedIo<'buf, I>
+where
+    I: AsyncRead + Unpin,
+{
+    /// Reads up to [`READ_CHUNK_SIZE`] more bytes (at least one) from the stream onto the end of
+    /// the buffer, without consuming anything already in it. Fails with `UnexpectedEof` if the
+    /// stream is closed.
+    pub async fn read(&mut self) -> Result<(), Error> {
+        let len = self.buffer.len();
+        self.buffer.resize(len + READ_CHUNK_SIZE, 0);
+
+        let n = self
+            .io
+            .read(&mut self.buffer[len..])
+            .await
+            .map_err(|e| Error::with_cause(ErrorKind::Io, e))?;
+        self.buffer.truncate(len + n);
+
+        if n == 0 {
+            return Err(Error::with_cause(
+                ErrorKind::Io,
+                std::io::Error::from(std::io::ErrorKind::UnexpectedEof),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl<'buf, I> BufferedIo<'buf, I>
+where
+    I: AsyncWrite + Unpin,
+{
+    /// Writes the buffer's contents to the stream and flushes it.
+    pub async fn write(&mut self) -> Result<(), Error> {
+        self.io
+            .write_all(&self.buffer[..])
+            .await
+            .map_err(|e| Error::with_cause(ErrorKind::Io, e))?;
+        self.io
+            .flush()
+            .await
+            .map_err(|e| Error::with_cause(ErrorKind::Io, e))
+    }
+}
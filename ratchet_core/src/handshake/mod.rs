@@ -12,12 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(test)]
-mod tests;
-
 mod client;
 mod io;
+mod redirect;
 mod server;
+mod sniff;
 mod subprotocols;
 
 use crate::errors::Error;
@@ -34,7 +33,13 @@ use tokio_util::codec::Decoder;
 use url::Url;
 
 pub use client::{subscribe, subscribe_with, HandshakeResult, UpgradedClient};
-pub use server::{accept, accept_with, UpgradedServer, WebSocketResponse, WebSocketUpgrader};
+pub use server::{
+    accept, accept_buffered, accept_extended_connect, accept_with, accept_with_buffered,
+    parse_extended_connect, ExtraResponseHeaders, HandshakeRejection, Interceptor,
+    ServerHandshakeResult, UpgradedServer, WebSocketResponse, WebSocketUpgrader,
+};
+pub use redirect::{RedirectOptions, RedirectOutcome, RedirectState};
+pub use sniff::{sniff, ConnectionKind, ProtocolSniffer};
 pub use subprotocols::*;
 
 const WEBSOCKET_STR: &str = "websocket";
@@ -44,6 +49,28 @@ const BAD_STATUS_CODE: &str = "Invalid status code";
 const ACCEPT_KEY: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 const METHOD_GET: &str = "get";
 
+const METHOD_CONNECT: &str = "connect";
+const PROTOCOL_WEBSOCKET: &str = "websocket";
+
+/// The transport that a handshake was negotiated over.
+///
+/// A handshake either follows the classic HTTP/1.1 Upgrade dance described by RFC 6455, or it
+/// arrives as an Extended CONNECT request on an existing HTTP/2 connection as described by
+/// RFC 8441. The two modes agree on subprotocol/extension negotiation but differ in how the
+/// request is framed, whether a `Sec-WebSocket-Key` is exchanged, and how success is signalled
+/// back to the peer, so the session layer needs to know which one was used in order to select
+/// the correct framing setup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportMode {
+    /// The handshake was negotiated with an HTTP/1.1 `GET` plus `Upgrade: websocket`, and is
+    /// accepted with a `101 Switching Protocols` response.
+    Http1Upgrade,
+    /// The handshake was negotiated with an HTTP/2 Extended CONNECT request
+    /// (`:method = CONNECT`, `:protocol = websocket`), and is accepted with a plain `200`
+    /// response.
+    Http2ExtendedConnect,
+}
+
 pub struct StreamingParser<'i, 'buf, I, P> {
     io: &'i mut BufferedIo<'buf, I>,
     parser: P,
@@ -87,13 +114,13 @@ pub trait TryIntoRequest {
     fn try_into_request(self) -> Result<Request, Error>;
 }
 
-impl<'a> TryIntoRequest for &'a str {
+impl TryIntoRequest for &str {
     fn try_into_request(self) -> Result<Request, Error> {
         self.parse::<Uri>()?.try_into_request()
     }
 }
 
-impl<'a> TryIntoRequest for &'a String {
+impl TryIntoRequest for &String {
     fn try_into_request(self) -> Result<Request, Error> {
         self.as_str().try_into_request()
     }
@@ -105,7 +132,7 @@ impl TryIntoRequest for String {
     }
 }
 
-impl<'a> TryIntoRequest for &'a Uri {
+impl TryIntoRequest for &Uri {
     fn try_into_request(self) -> Result<Request, Error> {
         self.clone().try_into_request()
     }
@@ -117,7 +144,7 @@ impl TryIntoRequest for Uri {
     }
 }
 
-impl<'a> TryIntoRequest for &'a Url {
+impl TryIntoRequest for &Url {
     fn try_into_request(self) -> Result<Request, Error> {
         self.as_str().try_into_request()
     }
@@ -212,7 +239,7 @@ impl<'h> TryMap<HeaderMap> for &'h [httparse::Header<'h>] {
     }
 }
 
-impl<'l, 'h, 'buf: 'h> TryMap<Request> for &'l httparse::Request<'h, 'buf> {
+impl<'h, 'buf: 'h> TryMap<Request> for &httparse::Request<'h, 'buf> {
     type Error = HttpError;
 
     fn try_map(self) -> Result<Request, Self::Error> {
@@ -0,0 +1,250 @@
+// Copyright 2015-2021 Swim Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Opt-in redirect following for the client handshake.
+//!
+//! `subscribe`/`subscribe_with` treat any non-`101` response as terminal by default. When
+//! [`RedirectOptions`] is supplied, a `3xx` response is instead resolved against the current
+//! `Uri` and the upgrade request is retried against the new location, up to a configurable hop
+//! count.
+
+use crate::errors::{Error, ErrorKind, HttpError};
+use crate::handshake::TryIntoRequest;
+use http::{HeaderMap, HeaderValue, Request, StatusCode, Uri};
+use std::collections::HashSet;
+
+/// Configuration for following redirects during the client handshake.
+#[derive(Debug, Clone, Copy)]
+pub struct RedirectOptions {
+    /// The maximum number of redirects to follow before giving up.
+    pub max_redirects: u32,
+    /// Whether a redirect from `wss` to `ws` is permitted. Disabled by default, as this silently
+    /// drops transport security.
+    pub allow_insecure_downgrade: bool,
+}
+
+impl Default for RedirectOptions {
+    fn default() -> Self {
+        RedirectOptions {
+            max_redirects: 5,
+            allow_insecure_downgrade: false,
+        }
+    }
+}
+
+/// The outcome of a single handshake attempt, as classified by the caller driving redirect
+/// resolution.
+pub enum RedirectOutcome<T> {
+    /// The handshake completed.
+    Complete(T),
+    /// The server replied with a `3xx` status and a `Location` header that should be followed.
+    Redirect {
+        status: StatusCode,
+        location: HeaderValue,
+    },
+}
+
+/// Tracks hop count and visited `Uri`s across a redirect chain, resolving each `3xx` response
+/// into the request to retry next.
+///
+/// This is driven directly by the client handshake loop rather than by a generic combinator: the
+/// handshake attempt borrows the stream mutably across `.await` points on every hop, which an
+/// `FnMut` closure cannot re-lend out to a caller-supplied future on each call.
+pub struct RedirectState {
+    visited: HashSet<Uri>,
+    hops: u32,
+}
+
+impl RedirectState {
+    /// Starts tracking a redirect chain beginning at `initial`.
+    pub fn new(initial: &Uri) -> RedirectState {
+        let mut visited = HashSet::new();
+        visited.insert(initial.clone());
+        RedirectState {
+            visited,
+            hops: 0,
+        }
+    }
+
+    /// Resolves a `3xx` response into the request to retry next, carrying over the current
+    /// request's headers (auth, cookies, subprotocol offer, ...) since only the URI and
+    /// handshake key are per-hop.
+    ///
+    /// Fails if `options`' hop limit is exceeded, the redirect would downgrade `wss` to `ws`
+    /// without `options.allow_insecure_downgrade`, or the chain revisits a `Uri`.
+    pub fn resolve(
+        &mut self,
+        options: &RedirectOptions,
+        current_uri: &Uri,
+        current_headers: &HeaderMap,
+        location: HeaderValue,
+    ) -> Result<Request<()>, Error> {
+        self.hops += 1;
+        if self.hops > options.max_redirects {
+            return Err(Error::with_cause(
+                ErrorKind::Http,
+                HttpError::TooManyRedirects(options.max_redirects),
+            ));
+        }
+
+        let next_uri = resolve_location(current_uri, &location)?;
+
+        if !options.allow_insecure_downgrade && is_downgrade(current_uri, &next_uri) {
+            return Err(Error::with_cause(
+                ErrorKind::Http,
+                HttpError::InsecureRedirect(next_uri.to_string()),
+            ));
+        }
+
+        if !self.visited.insert(next_uri.clone()) {
+            return Err(Error::with_cause(
+                ErrorKind::Http,
+                HttpError::RedirectLoop(next_uri.to_string()),
+            ));
+        }
+
+        // Carry the caller's original headers (auth, cookies, subprotocol offer, ...) over to
+        // the new location; only the URI and the handshake key are per-hop.
+        let mut next_request = next_uri.try_into_request()?;
+        *next_request.headers_mut() = current_headers.clone();
+        Ok(next_request)
+    }
+}
+
+/// Resolves a `Location` header against the currently active `Uri`, supporting both absolute and
+/// relative forms.
+fn resolve_location(current: &Uri, location: &HeaderValue) -> Result<Uri, Error> {
+    let malformed = || Error::with_cause(ErrorKind::Http, HttpError::MalformattedUri(None));
+    let location = location.to_str().map_err(|_| malformed())?;
+    let base = url::Url::parse(&current.to_string()).map_err(|_| malformed())?;
+    let target = base.join(location).map_err(|_| malformed())?;
+
+    target.as_str().parse::<Uri>().map_err(|_| malformed())
+}
+
+/// Whether following a redirect from `from` to `to` would downgrade from `wss` to `ws`.
+fn is_downgrade(from: &Uri, to: &Uri) -> bool {
+    matches!(
+        (from.scheme_str(), to.scheme_str()),
+        (Some("wss"), Some("ws"))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str) -> Request<()> {
+        Request::builder()
+            .uri(uri)
+            .header("Authorization", "Bearer token")
+            .body(())
+            .unwrap()
+    }
+
+    /// Regression test for a bug where the redirected request dropped the caller's original
+    /// headers (auth, cookies, subprotocol offer) instead of carrying them over to the new
+    /// location.
+    #[test]
+    fn preserves_request_headers_across_a_redirect() {
+        let current = request("ws://example.com/a");
+        let mut state = RedirectState::new(current.uri());
+
+        let next = state
+            .resolve(
+                &RedirectOptions::default(),
+                current.uri(),
+                current.headers(),
+                HeaderValue::from_static("ws://example.com/b"),
+            )
+            .unwrap();
+
+        assert_eq!(next.headers().get("Authorization").unwrap(), "Bearer token");
+        assert_eq!(next.uri().to_string(), "ws://example.com/b");
+    }
+
+    #[test]
+    fn refuses_a_downgrade_from_wss_to_ws_by_default() {
+        let current = request("wss://example.com/a");
+        let mut state = RedirectState::new(current.uri());
+
+        let err = state
+            .resolve(
+                &RedirectOptions::default(),
+                current.uri(),
+                current.headers(),
+                HeaderValue::from_static("ws://example.com/b"),
+            )
+            .unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::Http);
+    }
+
+    #[test]
+    fn stops_after_the_configured_hop_limit() {
+        let current = request("ws://example.com/a");
+        let mut state = RedirectState::new(current.uri());
+        let options = RedirectOptions {
+            max_redirects: 1,
+            allow_insecure_downgrade: false,
+        };
+
+        let next = state
+            .resolve(
+                &options,
+                current.uri(),
+                current.headers(),
+                HeaderValue::from_static("ws://example.com/b"),
+            )
+            .unwrap();
+
+        let err = state
+            .resolve(
+                &options,
+                next.uri(),
+                next.headers(),
+                HeaderValue::from_static("ws://example.com/c"),
+            )
+            .unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::Http);
+    }
+
+    #[test]
+    fn detects_a_redirect_loop() {
+        let current = request("ws://example.com/a");
+        let mut state = RedirectState::new(current.uri());
+        let options = RedirectOptions::default();
+
+        let next = state
+            .resolve(
+                &options,
+                current.uri(),
+                current.headers(),
+                HeaderValue::from_static("ws://example.com/b"),
+            )
+            .unwrap();
+
+        let err = state
+            .resolve(
+                &options,
+                next.uri(),
+                next.headers(),
+                HeaderValue::from_static("ws://example.com/a"),
+            )
+            .unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::Http);
+    }
+}
@@ -14,15 +14,16 @@
 
 use crate::ext::NegotiatedExtension;
 use crate::handshake::io::BufferedIo;
+use crate::handshake::server::intercept::HandshakeRejection;
 use crate::handshake::server::HandshakeResult;
 use crate::handshake::{
-    get_header, validate_header, validate_header_value, ParseResult, METHOD_GET, UPGRADE_STR,
-    WEBSOCKET_STR, WEBSOCKET_VERSION_STR,
+    get_header, validate_header, validate_header_value, ParseResult, METHOD_CONNECT, METHOD_GET,
+    PROTOCOL_WEBSOCKET, UPGRADE_STR, WEBSOCKET_STR, WEBSOCKET_VERSION_STR,
 };
-use crate::handshake::{negotiate_request, TryMap};
+use crate::handshake::{negotiate_request, TransportMode, TryMap};
 use crate::{Error, ErrorKind, HttpError, ProtocolRegistry};
-use bytes::{BufMut, BytesMut};
-use http::{HeaderMap, StatusCode};
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{HeaderMap, HeaderValue, StatusCode};
 use httparse::Status;
 use ratchet_ext::ExtensionProvider;
 use tokio::io::AsyncWrite;
@@ -35,8 +36,9 @@ const STATUS_TERMINATOR_LEN: usize = 2;
 const TERMINATOR_NO_HEADERS: &[u8] = b"\r\n\r\n";
 const TERMINATOR_WITH_HEADER: &[u8] = b"\r\n";
 const HTTP_VERSION_INT: u8 = 1;
+const DEFAULT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
 
-pub struct RequestParser<E> {
+pub(crate) struct RequestParser<E> {
     pub subprotocols: ProtocolRegistry,
     pub extension: E,
 }
@@ -70,12 +72,21 @@ pub async fn write_response<S>(
     stream: &mut S,
     buf: &mut BytesMut,
     status: StatusCode,
-    headers: HeaderMap,
+    mut headers: HeaderMap,
     body: Option<String>,
 ) -> Result<(), Error>
 where
     S: AsyncWrite + Unpin,
 {
+    if let Some(body) = &body {
+        headers
+            .entry(http::header::CONTENT_LENGTH)
+            .or_insert_with(|| HeaderValue::from(body.len()));
+        headers
+            .entry(http::header::CONTENT_TYPE)
+            .or_insert_with(|| HeaderValue::from_static(DEFAULT_CONTENT_TYPE));
+    }
+
     buf.clear();
 
     let version_count = HTTP_VERSION.len();
@@ -124,6 +135,24 @@ where
     buffered.write().await
 }
 
+/// Writes a [`HandshakeRejection`] returned by an [`Interceptor`](crate::handshake::Interceptor)
+/// in place of the upgrade response.
+pub async fn write_rejection<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    rejection: HandshakeRejection,
+) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    let HandshakeRejection {
+        status,
+        headers,
+        body,
+    } = rejection;
+    write_response(stream, buf, status, headers, body).await
+}
+
 pub fn try_parse_request<'l, E>(
     buffer: &'l [u8],
     request: &mut httparse::Request<'_, 'l>,
@@ -198,9 +227,7 @@ where
     let headers = &request.headers;
     validate_header(headers, http::header::CONNECTION, |name, value| {
         let mut parts = value.split(|char| char == &b',' || char == &b' ');
-        if parts.any(
-            |part| part.eq_ignore_ascii_case(UPGRADE_STR.as_bytes())
-        ) {
+        if parts.any(|part| part.eq_ignore_ascii_case(UPGRADE_STR.as_bytes())) {
             Ok(())
         } else {
             Err(Error::with_cause(
@@ -219,7 +246,7 @@ where
     validate_header(headers, http::header::HOST, |_, _| Ok(()))?;
 
     let key = get_header(headers, http::header::SEC_WEBSOCKET_KEY)?;
-    let subprotocol = negotiate_request(subprotocols, request)?;
+    let subprotocol = negotiate_request(subprotocols, headers)?;
     let extension_opt = extension
         .negotiate_server(request.headers)
         .map_err(|e| Error::with_cause(ErrorKind::Extension, e))?;
@@ -234,5 +261,80 @@ where
         extension,
         subprotocol,
         extension_header,
+        transport_mode: TransportMode::Http1Upgrade,
+    })
+}
+
+/// Validates and negotiates a WebSocket handshake delivered as an HTTP/2 Extended CONNECT
+/// request, as described in [RFC 8441](https://datatracker.ietf.org/doc/html/rfc8441).
+///
+/// Unlike [`parse_request`], there is no HTTP/1.1 request line or `Sec-WebSocket-Key`/accept-key
+/// exchange to parse: `method` and `protocol` come from the h2 stream's `:method`/`:protocol`
+/// pseudo-headers (surfaced by the embedding application via `h2::ext::Protocol`, not by this
+/// crate), and `authority`/`path` are likewise taken from `:authority`/`:path` to reconstruct the
+/// request's URI. `headers` is the regular (non-pseudo) header block, parsed with
+/// [`httparse::parse_headers`] since there is no request line for [`httparse::Request::parse`] to
+/// consume. The caller signals success with a plain `200` response instead of
+/// `101 Switching Protocols`. Subprotocol and extension negotiation are otherwise identical to
+/// [`parse_request`] and reuse the same machinery.
+pub fn parse_extended_connect<E>(
+    method: &str,
+    protocol: &str,
+    authority: &str,
+    path: &str,
+    headers: &[httparse::Header],
+    extension: E,
+    subprotocols: &mut ProtocolRegistry,
+) -> Result<HandshakeResult<E::Extension>, Error>
+where
+    E: ExtensionProvider,
+{
+    if !method.eq_ignore_ascii_case(METHOD_CONNECT) {
+        return Err(Error::with_cause(
+            ErrorKind::Http,
+            HttpError::HttpMethod(Some(method.to_string())),
+        ));
+    }
+
+    if !protocol.eq_ignore_ascii_case(PROTOCOL_WEBSOCKET) {
+        return Err(Error::with_cause(
+            ErrorKind::Http,
+            HttpError::InvalidHeader(http::header::UPGRADE),
+        ));
+    }
+
+    validate_header_value(
+        headers,
+        http::header::SEC_WEBSOCKET_VERSION,
+        WEBSOCKET_VERSION_STR,
+    )?;
+
+    let subprotocol = negotiate_request(subprotocols, headers)?;
+    let extension_opt = extension
+        .negotiate_server(headers)
+        .map_err(|e| Error::with_cause(ErrorKind::Extension, e))?;
+    let (extension, extension_header) = match extension_opt {
+        Some((extension, header)) => (NegotiatedExtension::from(Some(extension)), Some(header)),
+        None => (NegotiatedExtension::from(None), None),
+    };
+
+    let uri: http::Uri = format!("https://{}{}", authority, path)
+        .parse()
+        .map_err(|e: http::uri::InvalidUri| Error::with_cause(ErrorKind::Http, e))?;
+    let mut builder = http::Request::builder().method(METHOD_CONNECT).uri(uri);
+    if let Some(map) = builder.headers_mut() {
+        *map = headers.try_map()?;
+    }
+    let request = builder
+        .body(())
+        .map_err(|e| Error::with_cause(ErrorKind::Http, e))?;
+
+    Ok(HandshakeResult {
+        key: Bytes::new(),
+        request,
+        extension,
+        subprotocol,
+        extension_header,
+        transport_mode: TransportMode::Http2ExtendedConnect,
     })
 }
@@ -0,0 +1,86 @@
+// Copyright 2015-2021 Swim Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for inspecting or rejecting a handshake before it is committed, used by
+//! `accept_with`.
+
+use crate::Request;
+use http::{HeaderMap, StatusCode};
+
+/// Extra response headers to merge into the `101 Switching Protocols` response for a handshake
+/// that an [`Interceptor`] let through.
+#[derive(Debug, Default, Clone)]
+pub struct ExtraResponseHeaders(pub HeaderMap);
+
+/// A structured rejection of a handshake, written back to the client as a complete HTTP response
+/// in place of the WebSocket upgrade.
+#[derive(Debug, Clone)]
+pub struct HandshakeRejection {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Option<String>,
+}
+
+impl HandshakeRejection {
+    /// Rejects the handshake with `status` and no body.
+    pub fn new(status: StatusCode) -> HandshakeRejection {
+        HandshakeRejection {
+            status,
+            headers: HeaderMap::new(),
+            body: None,
+        }
+    }
+
+    /// Attaches a body to the rejection response. `Content-Length` and `Content-Type` are filled
+    /// in automatically when the response is written.
+    pub fn with_body<B>(mut self, body: B) -> HandshakeRejection
+    where
+        B: Into<String>,
+    {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Attaches additional headers to the rejection response.
+    pub fn with_headers(mut self, headers: HeaderMap) -> HandshakeRejection {
+        self.headers = headers;
+        self
+    }
+}
+
+/// A hook invoked by `accept_with` after header validation but before the upgrade is committed.
+///
+/// This gives a caller the chance to authenticate a client or inspect arbitrary request headers
+/// (cookies, `Origin`, auth tokens) before the `101` response is written. Returning `Ok` allows
+/// the handshake to continue, merging the returned headers into the upgrade response; returning
+/// `Err` rejects the handshake and the server writes the rejection's status, headers, and body
+/// instead.
+pub trait Interceptor {
+    /// Inspects `request`, accepting or rejecting the handshake.
+    // `HandshakeRejection` carries the full rejection response (status, headers, body), so it's
+    // naturally larger than a typical error; rejections are the uncommon path, so the extra copy
+    // isn't worth boxing for.
+    #[allow(clippy::result_large_err)]
+    fn intercept(&mut self, request: &Request) -> Result<ExtraResponseHeaders, HandshakeRejection>;
+}
+
+impl<F> Interceptor for F
+where
+    F: FnMut(&Request) -> Result<ExtraResponseHeaders, HandshakeRejection>,
+{
+    #[allow(clippy::result_large_err)]
+    fn intercept(&mut self, request: &Request) -> Result<ExtraResponseHeaders, HandshakeRejection> {
+        self(request)
+    }
+}
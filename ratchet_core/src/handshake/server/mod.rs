@@ -0,0 +1,362 @@
+// Copyright 2015-2021 Swim Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The server side of the handshake: parsing an incoming request, negotiating subprotocols and
+//! extensions, and writing back the response that completes the upgrade.
+
+mod encoding;
+mod intercept;
+
+use crate::errors::{Error, ErrorKind, HttpError};
+use crate::ext::NegotiatedExtension;
+use crate::handshake::io::BufferedIo;
+use crate::handshake::{StreamingParser, TransportMode, ACCEPT_KEY};
+use crate::{ProtocolRegistry, Request};
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use ratchet_ext::ExtensionProvider;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub use encoding::parse_extended_connect;
+pub use intercept::{ExtraResponseHeaders, HandshakeRejection, Interceptor};
+
+pub use HandshakeResult as ServerHandshakeResult;
+
+use encoding::{write_rejection, write_response, RequestParser};
+
+/// The outcome of successfully parsing and negotiating a handshake request, before the response
+/// that completes it has been written.
+pub struct HandshakeResult<E> {
+    pub key: Bytes,
+    pub request: Request,
+    pub extension: NegotiatedExtension<E>,
+    pub subprotocol: Option<Bytes>,
+    pub extension_header: Option<HeaderValue>,
+    pub transport_mode: TransportMode,
+}
+
+/// The HTTP response that completes (or, for an Extended CONNECT handshake, should be attached
+/// to) the upgrade.
+#[derive(Debug, Clone)]
+pub struct WebSocketResponse {
+    /// `101 Switching Protocols` for an HTTP/1.1 upgrade, `200` for an Extended CONNECT.
+    pub status: StatusCode,
+    /// The `Upgrade`/`Connection`/`Sec-WebSocket-*` headers describing the negotiated handshake.
+    pub headers: HeaderMap,
+}
+
+/// A stream that has completed a server-side WebSocket handshake.
+pub struct UpgradedServer<E> {
+    /// The subprotocol negotiated with the client, if any.
+    pub subprotocol: Option<Bytes>,
+    /// The extension negotiated with the client, if any.
+    pub extension: NegotiatedExtension<E>,
+    /// The transport the handshake was negotiated over.
+    pub transport_mode: TransportMode,
+    /// The response that completed the handshake.
+    ///
+    /// For [`TransportMode::Http1Upgrade`] this has already been written to the stream by
+    /// [`accept`]/[`accept_with`]. For [`TransportMode::Http2ExtendedConnect`], returned by
+    /// [`accept_extended_connect`], nothing has been written yet: the caller's h2 loop owns the
+    /// stream and must attach these headers to its own `200` response.
+    pub response: WebSocketResponse,
+}
+
+/// Reusable configuration for accepting server-side WebSocket handshakes, bundling the
+/// subprotocols and extension offered once so a caller doesn't have to thread them through every
+/// [`accept`]/[`accept_with`] call in a connection-accept loop.
+pub struct WebSocketUpgrader<E> {
+    subprotocols: ProtocolRegistry,
+    extension: E,
+}
+
+impl<E> WebSocketUpgrader<E>
+where
+    E: ExtensionProvider + Clone,
+{
+    /// Builds an upgrader that offers no subprotocols and negotiates `extension`.
+    pub fn new(extension: E) -> WebSocketUpgrader<E> {
+        WebSocketUpgrader {
+            subprotocols: ProtocolRegistry::default(),
+            extension,
+        }
+    }
+
+    /// Sets the subprotocols this upgrader offers.
+    pub fn subprotocols(mut self, subprotocols: ProtocolRegistry) -> WebSocketUpgrader<E> {
+        self.subprotocols = subprotocols;
+        self
+    }
+
+    /// Accepts a handshake from `stream`. See [`accept`].
+    pub async fn accept<S>(&self, stream: &mut S) -> Result<UpgradedServer<E::Extension>, Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        accept(stream, self.subprotocols.clone(), self.extension.clone()).await
+    }
+
+    /// Accepts a handshake from `stream`, running `interceptor` first. See [`accept_with`].
+    pub async fn accept_with<S, I>(
+        &self,
+        stream: &mut S,
+        interceptor: I,
+    ) -> Result<UpgradedServer<E::Extension>, Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        I: Interceptor,
+    {
+        accept_with(
+            stream,
+            self.subprotocols.clone(),
+            self.extension.clone(),
+            interceptor,
+        )
+        .await
+    }
+}
+
+/// Builds the `Sec-WebSocket-Accept` header value for `key`, per RFC 6455 section 1.3.
+fn accept_key(key: &[u8]) -> HeaderValue {
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update(ACCEPT_KEY);
+    let digest = hasher.finalize();
+    let encoded = base64::engine::general_purpose::STANDARD.encode(digest);
+    HeaderValue::from_str(&encoded).expect("base64 output is always a valid header value")
+}
+
+/// Builds the `101 Switching Protocols` response that completes an HTTP/1.1 upgrade handshake.
+fn upgrade_response(result: &HandshakeResult<impl Sized>) -> WebSocketResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(http::header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(
+        http::header::CONNECTION,
+        HeaderValue::from_static("Upgrade"),
+    );
+    headers.insert(http::header::SEC_WEBSOCKET_ACCEPT, accept_key(&result.key));
+
+    if let Some(subprotocol) = &result.subprotocol {
+        if let Ok(value) = HeaderValue::from_maybe_shared(subprotocol.clone()) {
+            headers.insert(http::header::SEC_WEBSOCKET_PROTOCOL, value);
+        }
+    }
+
+    if let Some(extension_header) = &result.extension_header {
+        headers.insert(
+            http::header::SEC_WEBSOCKET_EXTENSIONS,
+            extension_header.clone(),
+        );
+    }
+
+    WebSocketResponse {
+        status: StatusCode::SWITCHING_PROTOCOLS,
+        headers,
+    }
+}
+
+/// Accepts a server-side WebSocket handshake from `stream`, writing the `101 Switching Protocols`
+/// response once the request has been parsed and negotiated.
+pub async fn accept<S, E>(
+    stream: &mut S,
+    subprotocols: ProtocolRegistry,
+    extension: E,
+) -> Result<UpgradedServer<E::Extension>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    E: ExtensionProvider,
+{
+    accept_buffered(stream, BytesMut::new(), subprotocols, extension).await
+}
+
+/// Like [`accept`], but continues parsing from `buf` instead of starting from an empty buffer.
+///
+/// This is the other half of [`crate::handshake::sniff`]: a
+/// [`ConnectionKind::WebSocketUpgrade`](crate::handshake::ConnectionKind::WebSocketUpgrade)
+/// classification leaves `buf` holding the bytes already read off `stream` while sniffing, and
+/// passing that same buffer here means none of them are re-read or lost.
+pub async fn accept_buffered<S, E>(
+    stream: &mut S,
+    mut buf: BytesMut,
+    subprotocols: ProtocolRegistry,
+    extension: E,
+) -> Result<UpgradedServer<E::Extension>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    E: ExtensionProvider,
+{
+    let result = {
+        let mut io = BufferedIo::new(&mut *stream, &mut buf);
+        StreamingParser::new(
+            &mut io,
+            RequestParser {
+                subprotocols,
+                extension,
+            },
+        )
+        .parse()
+        .await?
+    };
+
+    let response = upgrade_response(&result);
+    write_response(
+        stream,
+        &mut buf,
+        response.status,
+        response.headers.clone(),
+        None,
+    )
+    .await?;
+
+    Ok(UpgradedServer {
+        subprotocol: result.subprotocol,
+        extension: result.extension,
+        transport_mode: result.transport_mode,
+        response,
+    })
+}
+
+/// Accepts a server-side WebSocket handshake from `stream`, running `interceptor` against the
+/// parsed request before the upgrade is committed.
+///
+/// This gives a caller the chance to authenticate a client or reject the handshake with a
+/// structured response (see [`Interceptor`]) before the `101` response is written.
+pub async fn accept_with<S, E, I>(
+    stream: &mut S,
+    subprotocols: ProtocolRegistry,
+    extension: E,
+    interceptor: I,
+) -> Result<UpgradedServer<E::Extension>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    E: ExtensionProvider,
+    I: Interceptor,
+{
+    accept_with_buffered(stream, BytesMut::new(), subprotocols, extension, interceptor).await
+}
+
+/// Like [`accept_with`], but continues parsing from `buf` instead of starting from an empty
+/// buffer. See [`accept_buffered`] for why this is needed after [`crate::handshake::sniff`].
+pub async fn accept_with_buffered<S, E, I>(
+    stream: &mut S,
+    mut buf: BytesMut,
+    subprotocols: ProtocolRegistry,
+    extension: E,
+    mut interceptor: I,
+) -> Result<UpgradedServer<E::Extension>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    E: ExtensionProvider,
+    I: Interceptor,
+{
+    let result = {
+        let mut io = BufferedIo::new(&mut *stream, &mut buf);
+        StreamingParser::new(
+            &mut io,
+            RequestParser {
+                subprotocols,
+                extension,
+            },
+        )
+        .parse()
+        .await?
+    };
+
+    match interceptor.intercept(&result.request) {
+        Ok(ExtraResponseHeaders(extra)) => {
+            let mut response = upgrade_response(&result);
+            response.headers.extend(extra);
+            write_response(
+                stream,
+                &mut buf,
+                response.status,
+                response.headers.clone(),
+                None,
+            )
+            .await?;
+
+            Ok(UpgradedServer {
+                subprotocol: result.subprotocol,
+                extension: result.extension,
+                transport_mode: result.transport_mode,
+                response,
+            })
+        }
+        Err(rejection) => {
+            write_rejection(stream, &mut buf, rejection).await?;
+            Err(Error::with_cause(
+                ErrorKind::Http,
+                HttpError::HandshakeRejected,
+            ))
+        }
+    }
+}
+
+/// Accepts a server-side WebSocket handshake delivered as an HTTP/2 Extended CONNECT request
+/// (RFC 8441).
+///
+/// Unlike [`accept`], this doesn't drive a raw byte stream: this crate intentionally doesn't
+/// depend on `h2`, so the embedding application's own h2 server loop owns the stream and must
+/// supply the `:method`/`:protocol`/`:authority`/`:path` pseudo-header values (read off
+/// `h2::ext::Protocol` and the request's `Uri`) and the regular header block. Nothing is written
+/// to the wire; the returned [`UpgradedServer::response`] holds the `200` status and
+/// `Sec-WebSocket-Protocol`/`Sec-WebSocket-Extensions` headers the caller should attach to its
+/// own response before sending it on the h2 stream.
+pub fn accept_extended_connect<E>(
+    method: &str,
+    protocol: &str,
+    authority: &str,
+    path: &str,
+    headers: &[httparse::Header],
+    mut subprotocols: ProtocolRegistry,
+    extension: E,
+) -> Result<UpgradedServer<E::Extension>, Error>
+where
+    E: ExtensionProvider,
+{
+    let result = parse_extended_connect(
+        method,
+        protocol,
+        authority,
+        path,
+        headers,
+        extension,
+        &mut subprotocols,
+    )?;
+
+    let mut response_headers = HeaderMap::new();
+    if let Some(subprotocol) = &result.subprotocol {
+        if let Ok(value) = HeaderValue::from_maybe_shared(subprotocol.clone()) {
+            response_headers.insert(http::header::SEC_WEBSOCKET_PROTOCOL, value);
+        }
+    }
+    if let Some(extension_header) = &result.extension_header {
+        response_headers.insert(
+            http::header::SEC_WEBSOCKET_EXTENSIONS,
+            extension_header.clone(),
+        );
+    }
+
+    Ok(UpgradedServer {
+        subprotocol: result.subprotocol,
+        extension: result.extension,
+        transport_mode: result.transport_mode,
+        response: WebSocketResponse {
+            status: StatusCode::OK,
+            headers: response_headers,
+        },
+    })
+}
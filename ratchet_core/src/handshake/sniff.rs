@@ -0,0 +1,167 @@
+// Copyright 2015-2021 Swim Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A non-consuming peek at an accepted connection, used to tell a WebSocket upgrade request
+//! apart from ordinary HTTP traffic so that one listener can serve both.
+
+use crate::errors::Error;
+use crate::handshake::io::BufferedIo;
+use crate::handshake::{StreamingParser, METHOD_GET, UPGRADE_STR, WEBSOCKET_STR};
+use bytes::BytesMut;
+use httparse::Status;
+use tokio::io::AsyncRead;
+use tokio_util::codec::Decoder;
+
+/// The maximum number of headers inspected while sniffing; only the request line plus the
+/// `Upgrade`/`Connection` headers are needed to classify a connection.
+const MAX_SNIFF_HEADERS: usize = 16;
+
+/// The classification of a connection's leading bytes, produced by [`ProtocolSniffer`].
+///
+/// Neither variant consumes any bytes from the underlying stream: a [`ConnectionKind::Other`]
+/// connection can be handed, buffer intact, to a plain-HTTP handler which re-parses the full
+/// request from the start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionKind {
+    /// The leading bytes form a `GET` request with `Connection: Upgrade` and
+    /// `Upgrade: websocket`. The connection should be handed to the existing `accept` path.
+    WebSocketUpgrade,
+    /// The leading bytes are some other HTTP method/verb, or a `GET` that is not a WebSocket
+    /// upgrade. The connection should be handed back to the caller's plain-HTTP handler.
+    Other,
+}
+
+/// A [`Decoder`] that classifies an accepted connection as a WebSocket upgrade or plain HTTP,
+/// without consuming any bytes. Drive it with [`crate::handshake::StreamingParser`]; the
+/// returned byte count is always `0`, so `StreamingParser::parse` never advances the buffer and
+/// the full request remains available for whichever handler the caller picks.
+///
+/// Most callers don't need to touch this directly: [`sniff`] drives it for you.
+#[derive(Debug, Default)]
+pub struct ProtocolSniffer;
+
+/// Peeks at `stream`'s leading bytes into `buf` and classifies the connection, without consuming
+/// anything: `buf` is left holding the full request, so it can be handed to
+/// [`crate::handshake::accept_buffered`]/[`crate::handshake::accept_with_buffered`] (for a
+/// [`ConnectionKind::WebSocketUpgrade`]) or to the caller's own HTTP handler (for
+/// [`ConnectionKind::Other`]) with nothing lost.
+///
+/// This is the intended entry point for sharing a listener between HTTP and WebSocket traffic;
+/// [`ProtocolSniffer`] is a lower-level building block most callers won't need directly.
+pub async fn sniff<S>(stream: &mut S, buf: &mut BytesMut) -> Result<ConnectionKind, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut io = BufferedIo::new(stream, buf);
+    StreamingParser::new(&mut io, ProtocolSniffer).parse().await
+}
+
+impl Decoder for ProtocolSniffer {
+    type Item = (ConnectionKind, usize);
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_SNIFF_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+
+        match request.parse(buf) {
+            Ok(Status::Complete(_)) => Ok(Some((classify(&request), 0))),
+            Ok(Status::Partial) => Ok(classify_partial(&request).map(|kind| (kind, 0))),
+            Err(_) => Ok(Some((ConnectionKind::Other, 0))),
+        }
+    }
+}
+
+fn classify(request: &httparse::Request) -> ConnectionKind {
+    let is_get = matches!(request.method, Some(m) if m.eq_ignore_ascii_case(METHOD_GET));
+    if is_get && has_upgrade_headers(request.headers) {
+        ConnectionKind::WebSocketUpgrade
+    } else {
+        ConnectionKind::Other
+    }
+}
+
+/// Classifies a request whose header block hasn't fully arrived yet, returning `None` if more
+/// bytes are needed before a decision can be made.
+fn classify_partial(request: &httparse::Request) -> Option<ConnectionKind> {
+    match request.method {
+        Some(m) if !m.eq_ignore_ascii_case(METHOD_GET) => Some(ConnectionKind::Other),
+        Some(_) if has_upgrade_headers(request.headers) => Some(ConnectionKind::WebSocketUpgrade),
+        _ => None,
+    }
+}
+
+fn has_upgrade_headers(headers: &[httparse::Header]) -> bool {
+    let mut connection_upgrade = false;
+    let mut upgrade_websocket = false;
+
+    for header in headers {
+        if header.name.is_empty() {
+            break;
+        }
+
+        if header.name.eq_ignore_ascii_case("Connection") {
+            connection_upgrade = header
+                .value
+                .split(|b| *b == b',' || *b == b' ')
+                .any(|part| part.eq_ignore_ascii_case(UPGRADE_STR.as_bytes()));
+        } else if header.name.eq_ignore_ascii_case("Upgrade") {
+            upgrade_websocket = header.value.eq_ignore_ascii_case(WEBSOCKET_STR.as_bytes());
+        }
+    }
+
+    connection_upgrade && upgrade_websocket
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(buf: &[u8]) -> Option<ConnectionKind> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_SNIFF_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        assert!(matches!(request.parse(buf), Ok(Status::Partial)));
+        classify_partial(&request)
+    }
+
+    #[test]
+    fn non_get_method_classifies_before_headers_arrive() {
+        assert_eq!(partial(b"POST /"), Some(ConnectionKind::Other));
+    }
+
+    #[test]
+    fn get_without_method_terminator_is_undecided() {
+        assert_eq!(partial(b"GE"), None);
+    }
+
+    #[test]
+    fn get_with_partial_headers_is_undecided_until_upgrade_headers_arrive() {
+        assert_eq!(partial(b"GET / HTTP/1.1\r\nHost: example.com\r\n"), None);
+    }
+
+    #[test]
+    fn get_with_upgrade_headers_classifies_before_the_blank_line_arrives() {
+        let buf = b"GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n";
+        assert_eq!(partial(buf), Some(ConnectionKind::WebSocketUpgrade));
+    }
+
+    #[test]
+    fn complete_get_without_upgrade_headers_is_other() {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_SNIFF_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        let buf = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert!(matches!(request.parse(buf), Ok(Status::Complete(_))));
+        assert_eq!(classify(&request), ConnectionKind::Other);
+    }
+}
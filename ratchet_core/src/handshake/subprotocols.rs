@@ -0,0 +1,88 @@
+// Copyright 2015-2021 Swim Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Subprotocol negotiation, driven by the `Sec-WebSocket-Protocol` header.
+
+use crate::errors::Error;
+use bytes::Bytes;
+
+const SEC_WEBSOCKET_PROTOCOL: &str = "Sec-WebSocket-Protocol";
+
+/// An ordered set of subprotocols a peer is willing to negotiate, most preferred first.
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolRegistry {
+    protocols: Vec<String>,
+}
+
+impl ProtocolRegistry {
+    /// Builds a registry from an ordered list of subprotocol names, most preferred first.
+    pub fn new<I, S>(protocols: I) -> ProtocolRegistry
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        ProtocolRegistry {
+            protocols: protocols.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// The comma-separated `Sec-WebSocket-Protocol` offer for this registry's subprotocols, or
+    /// `None` if it is empty.
+    pub fn offer(&self) -> Option<Bytes> {
+        if self.protocols.is_empty() {
+            None
+        } else {
+            Some(Bytes::from(self.protocols.join(", ")))
+        }
+    }
+
+    /// Whether `protocol` is one of this registry's subprotocols.
+    pub fn contains(&self, protocol: &str) -> bool {
+        self.protocols
+            .iter()
+            .any(|candidate| candidate.eq_ignore_ascii_case(protocol))
+    }
+}
+
+/// Selects the first of `registry`'s subprotocols that the peer also offered in
+/// `Sec-WebSocket-Protocol`, if any.
+pub fn negotiate_request(
+    registry: &mut ProtocolRegistry,
+    headers: &[httparse::Header],
+) -> Result<Option<Bytes>, Error> {
+    if registry.protocols.is_empty() {
+        return Ok(None);
+    }
+
+    let offered = match headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_PROTOCOL))
+    {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let offered = String::from_utf8_lossy(offered.value);
+
+    let negotiated = registry
+        .protocols
+        .iter()
+        .find(|protocol| {
+            offered
+                .split(',')
+                .any(|candidate| protocol.eq_ignore_ascii_case(candidate.trim()))
+        })
+        .map(|protocol| Bytes::from(protocol.clone()));
+
+    Ok(negotiated)
+}